@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+use crate::prelude::*;
+use common::SizeOf;
+
+/// Fixed per-version bookkeeping overhead (the `(Timestamp, Row)` link in a key's version
+/// chain) that a tagged/partial insert charges into `mem_size` on top of the row's own
+/// `deep_size_of`.
+pub(super) const VERSIONED_ROW_HEADER_SIZE: u64 = 16;
+
+static EMPTY_ROWS: Vec<Row> = Vec::new();
+
+/// The row storage backing a single index: a key's bucket keeps a list of the rows
+/// currently associated with it, and a parallel list of the timestamp each one was written
+/// at, so the same bucket can serve both "give me everything live" (plain `lookup`, which
+/// just borrows the row list) and "give me what was visible at `ts`" without an extra copy
+/// for the common, unversioned case.
+#[derive(Default)]
+pub(super) struct KeyedState {
+    rows: HashMap<Vec<DataType>, (Vec<Timestamp>, Vec<Row>)>,
+    holes: HashSet<Vec<DataType>>,
+}
+
+impl KeyedState {
+    pub(super) fn insert(&mut self, key: Vec<DataType>, row: Row, ts: Timestamp) {
+        let bucket = self.rows.entry(key).or_insert_with(Default::default);
+        bucket.0.push(ts);
+        bucket.1.push(row);
+    }
+
+    pub(super) fn remove(&mut self, key: &[DataType], value: &[DataType]) -> Option<Row> {
+        let bucket = self.rows.get_mut(key)?;
+        let pos = bucket.1.iter().position(|r| &**r == value)?;
+        bucket.0.remove(pos);
+        Some(bucket.1.remove(pos))
+    }
+
+    pub(super) fn lookup<'a>(&'a self, key: &KeyType, ts: Option<Timestamp>) -> LookupResult<'a> {
+        let key = key_to_vec(key);
+
+        if self.holes.contains(&key) {
+            return LookupResult::Missing;
+        }
+
+        match self.rows.get(&key) {
+            None => LookupResult::Some(RecordResult::Borrowed(&EMPTY_ROWS)),
+            Some((_, rows)) if ts.is_none() => LookupResult::Some(RecordResult::Borrowed(rows)),
+            Some((timestamps, rows)) => {
+                let ts = ts.unwrap();
+                let visible = timestamps
+                    .iter()
+                    .zip(rows.iter())
+                    .filter(|&(&row_ts, _)| row_ts <= ts)
+                    .map(|(_, row)| row.clone())
+                    .collect();
+                LookupResult::Some(RecordResult::Owned(visible))
+            }
+        }
+    }
+
+    pub(super) fn mark_filled(&mut self, key: Vec<DataType>) {
+        self.holes.remove(&key);
+    }
+
+    pub(super) fn mark_hole(&mut self, key: &[DataType]) -> u64 {
+        self.holes.insert(key.to_vec());
+        match self.rows.remove(key) {
+            Some((_, rows)) => rows.iter().map(SizeOf::deep_size_of).sum(),
+            None => 0,
+        }
+    }
+
+    pub(super) fn rows(&self) -> usize {
+        self.rows.values().map(|(_, rows)| rows.len()).sum()
+    }
+
+    pub(super) fn values(&self) -> impl Iterator<Item = &Vec<Row>> {
+        self.rows.values().map(|(_, rows)| rows)
+    }
+
+    pub(super) fn versioned_values(&self) -> impl Iterator<Item = (&Vec<Timestamp>, &Vec<Row>)> {
+        self.rows.values().map(|(ts, rows)| (ts, rows))
+    }
+
+    pub(super) fn evict_random_keys(
+        &mut self,
+        count: usize,
+        rng: &mut ThreadRng,
+    ) -> (u64, Vec<Vec<DataType>>) {
+        let mut freed = 0u64;
+        let mut evicted = Vec::new();
+        for _ in 0..count {
+            if self.rows.is_empty() {
+                break;
+            }
+            let i = rng.gen_range(0, self.rows.len());
+            let key = self.rows.keys().nth(i).unwrap().clone();
+            if let Some((_, rows)) = self.rows.remove(&key) {
+                freed += rows.iter().map(SizeOf::deep_size_of).sum::<u64>();
+            }
+            evicted.push(key);
+        }
+        (freed, evicted)
+    }
+
+    pub(super) fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
+        keys.iter()
+            .filter_map(|key| self.rows.remove(key))
+            .map(|(_, rows)| rows.iter().map(SizeOf::deep_size_of).sum::<u64>())
+            .sum()
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.rows.clear();
+        self.holes.clear();
+    }
+
+    /// Drops every version of a key older than `watermark` except the newest one, so reads
+    /// at or above `watermark` still find a value.
+    ///
+    /// This is only sound for a *partial* index: a key's bucket there holds successive
+    /// replay fills of the one value the domain currently has for that key, where an older
+    /// fill really is dead once a newer one lands, and `VERSIONED_ROW_HEADER_SIZE` is
+    /// charged per fill on insert. A full (non-partial) index's bucket instead holds
+    /// whatever distinct rows happen to share that key -- `lookup` hands back the whole
+    /// bucket as the result set -- so every entry there is live data, not a superseded
+    /// version, regardless of how old it is; see `SingleState::compact_versions`, which
+    /// only calls this for partial indices. A row's own bytes are only charged once its
+    /// last reference -- across every index sharing the `Rc` -- is actually gone, the same
+    /// way `MemoryState::remove` accounts for it.
+    pub(super) fn compact_versions(&mut self, watermark: Timestamp) -> u64 {
+        let mut freed = 0u64;
+        for (timestamps, rows) in self.rows.values_mut() {
+            let keep = match timestamps
+                .iter()
+                .enumerate()
+                .filter(|&(_, &ts)| ts < watermark)
+                .max_by_key(|&(_, &ts)| ts)
+                .map(|(i, _)| i)
+            {
+                Some(i) => i,
+                None => continue,
+            };
+
+            let mut to_drop: Vec<usize> = (0..timestamps.len())
+                .filter(|&i| i != keep && timestamps[i] < watermark)
+                .collect();
+            // Remove back-to-front so earlier indices in `to_drop` stay valid.
+            to_drop.sort_unstable_by(|a, b| b.cmp(a));
+
+            for i in to_drop {
+                timestamps.remove(i);
+                let row = rows.remove(i);
+                if Rc::strong_count(&row.0) == 1 {
+                    freed += row.deep_size_of() + VERSIONED_ROW_HEADER_SIZE;
+                }
+            }
+        }
+        freed
+    }
+}
+
+fn key_to_vec(key: &KeyType) -> Vec<DataType> {
+    match key {
+        KeyType::Single(a) => vec![(*a).clone()],
+        KeyType::Double((a, b)) => vec![a.clone(), b.clone()],
+        KeyType::Tri((a, b, c)) => vec![a.clone(), b.clone(), c.clone()],
+        _ => unimplemented!("key arity not yet supported by KeyedState"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(v: Vec<DataType>) -> Row {
+        Row::from(Rc::new(v))
+    }
+
+    #[test]
+    fn compact_versions_prunes_only_the_superseded_fill() {
+        let mut state = KeyedState::default();
+        let key = vec![1.into()];
+
+        state.insert(key.clone(), row(vec![1.into(), "old".into()]), 5);
+        state.insert(key.clone(), row(vec![1.into(), "new".into()]), 8);
+
+        let freed = state.compact_versions(10);
+        assert!(freed > 0);
+
+        match state.lookup(&KeyType::Single(&1.into()), None) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&*rows[0], &vec![1.into(), "new".into()]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn compact_versions_keeps_everything_at_or_after_the_watermark() {
+        let mut state = KeyedState::default();
+        let key = vec![1.into()];
+
+        state.insert(key.clone(), row(vec![1.into(), "old".into()]), 5);
+        state.insert(key.clone(), row(vec![1.into(), "new".into()]), 8);
+
+        let freed = state.compact_versions(6);
+        assert_eq!(freed, 0);
+
+        match state.lookup(&KeyType::Single(&1.into()), None) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(rows.len(), 2),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -150,14 +150,14 @@ impl State for MemoryState {
         self.state.iter().map(|s| s.key().to_vec()).collect()
     }
 
-    fn cloned_records(&self) -> Vec<Vec<DataType>> {
+    fn all_records(&self) -> Box<dyn Iterator<Item = Vec<DataType>> + '_> {
         #[allow(clippy::ptr_arg)]
         fn fix<'a>(rs: &'a Vec<Row>) -> impl Iterator<Item = Vec<DataType>> + 'a {
             rs.iter().map(|r| Vec::clone(&**r))
         }
 
         assert!(!self.state[0].partial());
-        self.state[0].values().flat_map(fix).collect()
+        Box::new(self.state[0].values().flat_map(fix))
     }
 
     fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
@@ -185,6 +185,13 @@ impl State for MemoryState {
         }
         self.mem_size = 0;
     }
+
+    fn compact_versions(&mut self, watermark: Timestamp) {
+        for state in &mut self.state {
+            let freed = state.compact_versions(watermark);
+            self.mem_size = self.mem_size.checked_sub(freed).unwrap();
+        }
+    }
 }
 
 impl MemoryState {
@@ -224,13 +231,13 @@ impl MemoryState {
     fn remove(&mut self, r: &[DataType], ts: Timestamp) -> bool {
         let mut hit = false;
         for s in &mut self.state {
-            s.remove_row(r, &mut hit, ts);
-            // TODO: Vaccum the unused records
-            // if let Some(row) = s.remove_row(r, &mut hit, ts) {
-            //     if Rc::strong_count(&row.0) == 1 {
-            //         self.mem_size = self.mem_size.checked_sub(row.deep_size_of()).unwrap();
-            //     }
-            // }
+            if let Some(row) = s.remove_row(r, &mut hit, ts) {
+                // the Negative we just processed was the last reference to this row shared
+                // across indices, so its bytes are actually gone now -- account for them.
+                if Rc::strong_count(&row.0) == 1 {
+                    self.mem_size = self.mem_size.checked_sub(row.deep_size_of()).unwrap();
+                }
+            }
         }
 
         hit
@@ -295,4 +302,38 @@ mod tests {
             _ => unreachable!(),
         };
     }
+
+    #[test]
+    fn memory_state_remove_vacuums_mem_size() {
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+
+        let row: Vec<DataType> = vec![1.into(), "A".into()];
+        state.process_records(&mut vec![(row.clone(), true)].into(), 0, None);
+        assert!(state.deep_size_of() > 0);
+
+        state.process_records(&mut vec![(row.clone(), false)].into(), 1, None);
+        assert_eq!(state.deep_size_of(), 0);
+    }
+
+    #[test]
+    fn compact_versions_keeps_live_rows_on_a_full_secondary_index() {
+        let mut state = MemoryState::default();
+        // Keyed on column 1, which both rows below share -- a non-unique secondary index.
+        state.add_key(&[1], None);
+
+        let a: Vec<DataType> = vec![1.into(), "shared".into()];
+        let b: Vec<DataType> = vec![2.into(), "shared".into()];
+        state.process_records(&mut vec![(a, true)].into(), 5, None);
+        state.process_records(&mut vec![(b, true)].into(), 6, None);
+
+        // Both rows are live and distinct; compacting past both of their insert
+        // timestamps must not collapse the bucket down to "the newest one".
+        state.compact_versions(10);
+
+        match state.lookup(&[1], &KeyType::Single(&"shared".into())) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(rows.len(), 2),
+            _ => unreachable!(),
+        };
+    }
 }
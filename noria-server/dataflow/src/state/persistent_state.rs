@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use bincode;
+use rocksdb::{self, ColumnFamily, WriteBatch, DB};
+
+use crate::prelude::*;
+use common::SizeOf;
+
+/// A durable, on-disk sibling of [`MemoryState`](super::MemoryState).
+///
+/// Base tables have to fit entirely in `MemoryState` today, which caps how much data a
+/// single node can hold and makes replay memory-bound. `PersistentState` backs a base table
+/// with an embedded log-structured key-value store (RocksDB) instead: each declared index
+/// gets its own column family keyed by the serialized index columns, writes go to every
+/// column family in one batch, and `all_records` streams off of a RocksDB iterator, which
+/// pins a consistent read view at creation time, so a replay thread never blocks concurrent
+/// writers.
+///
+/// Derived/partial views still belong in `MemoryState` -- only base tables that have opted
+/// into persistence should use this type.
+pub struct PersistentState {
+    db: DB,
+    // Indices in declaration order; `indices[i]` backs the column family named
+    // `indices[i].cf_name`.
+    indices: Vec<PersistentIndex>,
+}
+
+struct PersistentIndex {
+    cf_name: String,
+    columns: Vec<usize>,
+}
+
+/// Column family holding the one piece of state that isn't implied by the rest of RocksDB's
+/// own on-disk layout: which column set each `index_N` column family was created for. Without
+/// it, reopening an existing table would have no way to repopulate `self.indices`.
+const META_CF: &str = "noria_persistent_state_meta";
+const INDICES_KEY: &[u8] = b"indices";
+
+impl PersistentState {
+    /// Opens (creating if necessary) a `PersistentState` backed by a RocksDB instance rooted
+    /// at `path`. Column families created by a previous run are reopened, and their index
+    /// metadata is restored from `META_CF` rather than lost.
+    pub fn new(path: PathBuf) -> Self {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let mut cf_names = DB::list_cf(&opts, &path).unwrap_or_default();
+        if !cf_names.iter().any(|cf| cf == META_CF) {
+            cf_names.push(META_CF.to_string());
+        }
+
+        let db =
+            DB::open_cf(&opts, &path, &cf_names).expect("failed to open PersistentState RocksDB");
+
+        let meta_cf = db.cf_handle(META_CF).expect("just opened META_CF");
+        let indices = match db
+            .get_cf(meta_cf, INDICES_KEY)
+            .expect("RocksDB read failed")
+        {
+            Some(bytes) => {
+                let columns: Vec<Vec<usize>> =
+                    bincode::deserialize(&bytes).expect("corrupt PersistentState index metadata");
+                columns
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, columns)| PersistentIndex {
+                        cf_name: format!("index_{}", i),
+                        columns,
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        Self { db, indices }
+    }
+
+    fn index_for(&self, columns: &[usize]) -> Option<usize> {
+        self.indices.iter().position(|idx| idx.columns == columns)
+    }
+
+    fn cf(&self, index: usize) -> &ColumnFamily {
+        self.db
+            .cf_handle(&self.indices[index].cf_name)
+            .expect("missing column family for declared index")
+    }
+
+    fn meta_cf(&self) -> &ColumnFamily {
+        self.db.cf_handle(META_CF).expect("META_CF always open")
+    }
+
+    /// Persists the current `columns` of every index to `META_CF` so a future `new()` can
+    /// rebuild `self.indices` without having replayed every `add_key` call.
+    fn persist_index_metadata(&self) {
+        let columns: Vec<&Vec<usize>> = self.indices.iter().map(|idx| &idx.columns).collect();
+        self.db
+            .put_cf(
+                self.meta_cf(),
+                INDICES_KEY,
+                bincode::serialize(&columns).unwrap(),
+            )
+            .expect("failed to persist PersistentState index metadata");
+    }
+
+    /// Serializes an already-projected index key. Every index has its own column family, so
+    /// there's no need to disambiguate arity or index identity here -- just the key values.
+    fn serialize_key(key: &[DataType]) -> Vec<u8> {
+        bincode::serialize(key).expect("failed to serialize index key")
+    }
+
+    /// Projects a lookup `KeyType` into the same `Vec<DataType>` shape that writes are keyed
+    /// by, so reads and writes always hash to the same bytes.
+    fn key_to_vec(key: &KeyType) -> Vec<DataType> {
+        match key {
+            KeyType::Single(a) => vec![(*a).clone()],
+            KeyType::Double((a, b)) => vec![a.clone(), b.clone()],
+            KeyType::Tri((a, b, c)) => vec![a.clone(), b.clone(), c.clone()],
+            _ => unimplemented!("key arity not yet supported by PersistentState"),
+        }
+    }
+
+    fn read_bucket(&self, index: usize, key: &[u8]) -> Vec<Vec<DataType>> {
+        self.db
+            .get_cf(self.cf(index), key)
+            .expect("RocksDB read failed")
+            .map(|bytes| {
+                bincode::deserialize(&bytes).expect("corrupt PersistentState row batch")
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl SizeOf for PersistentState {
+    fn size_of(&self) -> u64 {
+        use std::mem::size_of;
+
+        size_of::<Self>() as u64
+    }
+
+    fn deep_size_of(&self) -> u64 {
+        // Memory pressure eviction doesn't apply to on-disk state -- there's nothing to
+        // evict, since RocksDB manages its own memtable/block-cache budgets.
+        0
+    }
+}
+
+impl State for PersistentState {
+    fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
+        assert!(
+            partial.is_none(),
+            "PersistentState only backs fully materialized base tables"
+        );
+
+        if self.index_for(columns).is_some() {
+            return;
+        }
+
+        let cf_name = format!("index_{}", self.indices.len());
+        self.db
+            .create_cf(&cf_name, &rocksdb::Options::default())
+            .expect("failed to create column family for new index");
+
+        self.indices.push(PersistentIndex {
+            cf_name,
+            columns: columns.to_vec(),
+        });
+        self.persist_index_metadata();
+    }
+
+    fn is_useful(&self) -> bool {
+        !self.indices.is_empty()
+    }
+
+    fn is_partial(&self) -> bool {
+        false
+    }
+
+    fn process_records(&mut self, records: &mut Records, ts: Timestamp, partial_tag: Option<Tag>) {
+        assert!(
+            partial_tag.is_none(),
+            "PersistentState only backs fully materialized base tables"
+        );
+        let _ = ts; // base tables don't retain historical versions on disk today
+
+        // Accumulate per-key buckets in memory across the whole batch before writing
+        // anything back: two records in the same batch can map to the same index key (any
+        // non-unique/secondary index, or a bulk base-table load), and reading straight from
+        // `self.db` for each one would have the second `put_cf` clobber the first.
+        let mut staged: HashMap<(usize, Vec<u8>), Vec<Vec<DataType>>> = HashMap::new();
+        for r in records.iter() {
+            match *r {
+                Record::Positive(ref r) => self.stage_insert(&mut staged, r),
+                Record::Negative(ref r) => self.stage_remove(&mut staged, r),
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for ((index, key), rows) in staged {
+            batch
+                .put_cf(self.cf(index), key, bincode::serialize(&rows).unwrap())
+                .expect("failed to stage PersistentState write");
+        }
+        self.db
+            .write(batch)
+            .expect("failed to write PersistentState batch");
+    }
+
+    fn rows(&self) -> usize {
+        // `rows()` means total row count, not key count (see MemoryState::rows) -- and with
+        // a non-unique index a key's bucket can hold more than one row -- so there's no
+        // RocksDB property that gives this directly; walk the primary index's buckets and
+        // sum their lengths the same way `all_records` does.
+        self.indices
+            .first()
+            .map(|idx| {
+                let cf = self.db.cf_handle(&idx.cf_name).unwrap();
+                self.db
+                    .iterator_cf(cf, rocksdb::IteratorMode::Start)
+                    .map(|(_key, value)| {
+                        let rows: Vec<Vec<DataType>> = bincode::deserialize(&value)
+                            .expect("corrupt PersistentState row batch");
+                        rows.len()
+                    })
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    fn mark_filled(&mut self, _key: Vec<DataType>, _tag: Tag) {
+        unreachable!("PersistentState is always fully materialized and has no holes to fill");
+    }
+
+    fn mark_hole(&mut self, _key: &[DataType], _tag: Tag) {
+        unreachable!("PersistentState is always fully materialized and has no holes to mark");
+    }
+
+    fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a> {
+        self.lookup_at(columns, key, None)
+    }
+
+    fn lookup_at<'a>(
+        &'a self,
+        columns: &[usize],
+        key: &KeyType,
+        _ts: Option<Timestamp>,
+    ) -> LookupResult<'a> {
+        let index = self
+            .index_for(columns)
+            .expect("lookup on non-indexed column set");
+        // Project the lookup key the same way inserts project rows, so reads and writes
+        // hash to identical bytes.
+        let raw_key = Self::serialize_key(&Self::key_to_vec(key));
+        let rows = self
+            .read_bucket(index, &raw_key)
+            .into_iter()
+            .map(|row| Row::from(Rc::new(row)))
+            .collect();
+
+        LookupResult::Some(RecordResult::Owned(rows))
+    }
+
+    fn keys(&self) -> Vec<Vec<usize>> {
+        self.indices.iter().map(|idx| idx.columns.clone()).collect()
+    }
+
+    fn all_records(&self) -> Box<dyn Iterator<Item = Vec<DataType>> + '_> {
+        assert!(!self.indices.is_empty());
+        let cf = self.cf(0);
+        Box::new(
+            self.db
+                .iterator_cf(cf, rocksdb::IteratorMode::Start)
+                .flat_map(|(_key, value)| {
+                    let rows: Vec<Vec<DataType>> =
+                        bincode::deserialize(&value).expect("corrupt PersistentState row batch");
+                    rows
+                }),
+        )
+    }
+
+    fn evict_random_keys(&mut self, _count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        unreachable!("PersistentState is not subject to memory-pressure eviction");
+    }
+
+    fn evict_keys(&mut self, _tag: Tag, _keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
+        unreachable!("PersistentState is not subject to memory-pressure eviction");
+    }
+
+    fn clear(&mut self) {
+        for index in 0..self.indices.len() {
+            let cf_name = self.indices[index].cf_name.clone();
+            self.db
+                .drop_cf(&cf_name)
+                .expect("failed to drop column family");
+            self.db
+                .create_cf(&cf_name, &rocksdb::Options::default())
+                .expect("failed to recreate column family");
+        }
+    }
+}
+
+impl PersistentState {
+    fn stage_insert(
+        &self,
+        staged: &mut HashMap<(usize, Vec<u8>), Vec<Vec<DataType>>>,
+        r: &[DataType],
+    ) {
+        for (i, index) in self.indices.iter().enumerate() {
+            let key = Self::serialize_key(&project(&index.columns, r));
+            let rows = staged
+                .entry((i, key.clone()))
+                .or_insert_with(|| self.read_bucket(i, &key));
+            rows.push(r.to_vec());
+        }
+    }
+
+    fn stage_remove(
+        &self,
+        staged: &mut HashMap<(usize, Vec<u8>), Vec<Vec<DataType>>>,
+        r: &[DataType],
+    ) {
+        for (i, index) in self.indices.iter().enumerate() {
+            let key = Self::serialize_key(&project(&index.columns, r));
+            let rows = staged
+                .entry((i, key.clone()))
+                .or_insert_with(|| self.read_bucket(i, &key));
+            if let Some(pos) = rows.iter().position(|row| row == r) {
+                rows.remove(pos);
+            }
+        }
+    }
+}
+
+fn project(columns: &[usize], r: &[DataType]) -> Vec<DataType> {
+    columns.iter().map(|&i| r[i].clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn persistent_state_insert_and_lookup() {
+        let dir = tempdir().unwrap();
+        let mut state = PersistentState::new(dir.path().join("db"));
+        state.add_key(&[0], None);
+
+        let row: Vec<DataType> = vec![1.into(), "A".into()];
+        state.process_records(&mut vec![(row.clone(), true)].into(), 0, None);
+
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&*rows[0], &row);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_batches_multiple_records_for_the_same_key() {
+        let dir = tempdir().unwrap();
+        let mut state = PersistentState::new(dir.path().join("db"));
+        // Non-unique secondary index: both rows below share a key.
+        state.add_key(&[1], None);
+
+        let a: Vec<DataType> = vec![1.into(), "shared".into()];
+        let b: Vec<DataType> = vec![2.into(), "shared".into()];
+        state.process_records(
+            &mut vec![(a, true), (b, true)].into(),
+            0,
+            None,
+        );
+
+        match state.lookup(&[1], &KeyType::Single(&"shared".into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows.len(), 2),
+            _ => unreachable!(),
+        }
+        assert_eq!(state.rows(), 2);
+    }
+
+    #[test]
+    fn persistent_state_remove() {
+        let dir = tempdir().unwrap();
+        let mut state = PersistentState::new(dir.path().join("db"));
+        state.add_key(&[0], None);
+
+        let row: Vec<DataType> = vec![1.into(), "A".into()];
+        state.process_records(&mut vec![(row.clone(), true)].into(), 0, None);
+        state.process_records(&mut vec![(row, false)].into(), 1, None);
+
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows.len(), 0),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_all_records() {
+        let dir = tempdir().unwrap();
+        let mut state = PersistentState::new(dir.path().join("db"));
+        state.add_key(&[0], None);
+
+        let rows: Vec<Vec<DataType>> =
+            vec![vec![1.into(), "A".into()], vec![2.into(), "B".into()]];
+        for row in &rows {
+            state.process_records(&mut vec![(row.clone(), true)].into(), 0, None);
+        }
+
+        let seen: Vec<Vec<DataType>> = state.all_records().collect();
+        assert_eq!(seen.len(), rows.len());
+        for row in &rows {
+            assert!(seen.contains(row));
+        }
+    }
+
+    #[test]
+    fn persistent_state_survives_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let row: Vec<DataType> = vec![1.into(), "A".into()];
+        {
+            let mut state = PersistentState::new(path.clone());
+            state.add_key(&[0], None);
+            state.process_records(&mut vec![(row.clone(), true)].into(), 0, None);
+        }
+
+        // A fresh PersistentState over the same path must rediscover the index that was
+        // declared before the restart, not just the raw column families.
+        let state = PersistentState::new(path);
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(&*rows[0], &row);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use rand::rngs::ThreadRng;
+
+use crate::prelude::*;
+use crate::state::keyed_state::KeyedState;
+
+/// One index over a `MemoryState`: the columns it's keyed on, whether it's a partial
+/// (replay-filled) index, and the row storage itself.
+pub(super) struct SingleState {
+    columns: Vec<usize>,
+    partial: bool,
+    state: KeyedState,
+}
+
+impl SingleState {
+    pub(super) fn new(columns: &[usize], partial: bool) -> Self {
+        Self {
+            columns: columns.to_vec(),
+            partial,
+            state: KeyedState::default(),
+        }
+    }
+
+    pub(super) fn partial(&self) -> bool {
+        self.partial
+    }
+
+    pub(super) fn key(&self) -> &[usize] {
+        &self.columns
+    }
+
+    fn key_for(&self, r: &[DataType]) -> Vec<DataType> {
+        self.columns.iter().map(|&i| r[i].clone()).collect()
+    }
+
+    pub(super) fn insert_row(&mut self, row: Row, ts: Timestamp) -> bool {
+        let key = self.key_for(&row);
+        self.state.insert(key, row, ts);
+        true
+    }
+
+    pub(super) fn insert_row_with_header(&mut self, row: Row, ts: Timestamp) {
+        let key = self.key_for(&row);
+        self.state.insert(key, row, ts);
+    }
+
+    /// Removes the row matching `r` from this index and hands it back, so the caller can
+    /// check whether this was the row's last reference before accounting for its memory.
+    /// `ts` is unused today -- deletes take effect immediately rather than leaving a
+    /// tombstone -- but is threaded through for parity with `insert_row`.
+    pub(super) fn remove_row(&mut self, r: &[DataType], hit: &mut bool, _ts: Timestamp) -> Option<Row> {
+        let key = self.key_for(r);
+        let removed = self.state.remove(&key, r);
+        if removed.is_some() {
+            *hit = true;
+        }
+        removed
+    }
+
+    pub(super) fn mark_filled(&mut self, key: Vec<DataType>) {
+        self.state.mark_filled(key);
+    }
+
+    pub(super) fn mark_hole(&mut self, key: &[DataType]) -> u64 {
+        self.state.mark_hole(key)
+    }
+
+    pub(super) fn lookup(&self, key: &KeyType, ts: Option<Timestamp>) -> LookupResult {
+        self.state.lookup(key, ts)
+    }
+
+    pub(super) fn rows(&self) -> usize {
+        self.state.rows()
+    }
+
+    pub(super) fn values(&self) -> impl Iterator<Item = &Vec<Row>> {
+        self.state.values()
+    }
+
+    pub(super) fn versioned_values(&self) -> impl Iterator<Item = (&Vec<Timestamp>, &Vec<Row>)> {
+        self.state.versioned_values()
+    }
+
+    pub(super) fn evict_random_keys(
+        &mut self,
+        count: usize,
+        rng: &mut ThreadRng,
+    ) -> (u64, Vec<Vec<DataType>>) {
+        self.state.evict_random_keys(count, rng)
+    }
+
+    pub(super) fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
+        self.state.evict_keys(keys)
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.state.clear();
+    }
+
+    /// See `KeyedState::compact_versions`: a key's bucket in a full (non-partial) index
+    /// holds genuinely distinct live rows that happen to share a key, not superseded
+    /// versions of one replay fill, so compaction must leave those untouched entirely.
+    pub(super) fn compact_versions(&mut self, watermark: Timestamp) -> u64 {
+        if !self.partial {
+            return 0;
+        }
+        self.state.compact_versions(watermark)
+    }
+}